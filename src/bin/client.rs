@@ -1,10 +1,14 @@
 mod chat_message;
+mod crypto;
 
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
 use rand::seq::IteratorRandom;
-use tokio::{io::{self, AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::mpsc::{Receiver, Sender}};
+use tokio::{io::{self}, net::TcpStream, sync::mpsc::{Receiver, Sender}};
 use tokio::sync::mpsc;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use futures::{SinkExt, StreamExt};
 use std::fs;
-use chat_message::ChatMessage;
+use chat_message::{ChatMessage, ServerEvent};
 use std::error::Error;
 
 use crossterm::{
@@ -26,17 +30,20 @@ struct App {
     /// History of recorded messages
     messages: Vec<String>,
     send_msg_tx: Sender<ChatMessage>,
-    receive_msg_rx: Receiver<ChatMessage>,
-    username: String
+    receive_msg_rx: Receiver<ServerEvent>,
+    username: String,
+    /// Number of users currently connected, as last reported by the server
+    users_online: usize
 }
 
 impl App {
-    const fn new(send_msg_tx: Sender<ChatMessage>, receive_msg_rx: Receiver<ChatMessage>, username: String) -> Self {
+    const fn new(send_msg_tx: Sender<ChatMessage>, receive_msg_rx: Receiver<ServerEvent>, username: String) -> Self {
         Self {
             input: String::new(),
             messages: Vec::new(),
             character_index: 0,
-            send_msg_tx, receive_msg_rx, username
+            send_msg_tx, receive_msg_rx, username,
+            users_online: 0
         }
     }
 
@@ -98,8 +105,20 @@ impl App {
         self.character_index = 0;
     }
 
-    fn receive_message(&mut self, message: ChatMessage) {
-        self.messages.push(message.to_string());
+    fn receive_message(&mut self, event: ServerEvent) {
+        match event {
+            ServerEvent::Chat(message) => self.messages.push(message.to_string()),
+            ServerEvent::Joined { username, users_online } => {
+                self.users_online = users_online;
+                self.messages.push(format!("* {username} joined"));
+            }
+            ServerEvent::Left { username, users_online } => {
+                self.users_online = users_online;
+                self.messages.push(format!("* {username} left"));
+            }
+            ServerEvent::Presence { users_online } => self.users_online = users_online,
+            ServerEvent::Shutdown => {}
+        }
     }
 
     fn submit_message(&mut self) {
@@ -124,44 +143,87 @@ fn generate_name() -> String {
     let rand_adjective = fs::read_to_string("english-adjectives.txt").unwrap().lines().choose(&mut rand::thread_rng()).unwrap().to_string();
     let rand_noun = fs::read_to_string("nounlist.txt").unwrap().lines().choose(&mut rand::thread_rng()).unwrap().to_string();
 
-    String::from(rand_adjective + "-" + rand_noun.as_str())
+    rand_adjective + "-" + rand_noun.as_str()
 }
 
-async fn run_network(receive_msg_tx: Sender<ChatMessage>, mut send_msg_rx: Receiver<ChatMessage>) {
+async fn run_network(receive_msg_tx: Sender<ServerEvent>, mut send_msg_rx: Receiver<ChatMessage>, username: String) {
     // Connect to the server
     let socket = TcpStream::connect("127.0.0.1:6969").await.unwrap();
-        
-    // Split the socket in two parts
-    let (mut rd, mut wt) = io::split(socket);
+
+    // Split the socket in two parts and wrap each half in a line-delimited codec
+    // so every frame is exactly one message, no matter how the stream is chunked
+    let (rd, wt) = io::split(socket);
+    let mut framed_rd = FramedRead::new(rd, LinesCodec::new());
+    let mut framed_wt = FramedWrite::new(wt, LinesCodec::new());
+
+    // Establish the encrypted session before any message flows. We're the
+    // initiator of the Diffie-Hellman handshake.
+    let (mut sealer, mut opener) = crypto::handshake(&mut framed_rd, &mut framed_wt, true)
+        .await
+        .expect("encrypted handshake failed");
 
     // Sending message task
-    let write_t = tokio::spawn(async move {
-        
-        // Wait for send event from the UI
+    let mut write_t = tokio::spawn(async move {
+
+        // Announce ourselves: the first sealed frame is our username handshake
+        framed_wt.send(B64.encode(sealer.seal(username.as_bytes()).unwrap())).await.unwrap();
+
+        // Wait for send event from the UI. When the UI drops its send side (on
+        // Esc) this loop ends, and we flush so the last queued message is
+        // delivered instead of being abandoned mid-write.
         while let Some(message) = send_msg_rx.recv().await {
-            // Send input to the server
-            wt.write(message.to_string().as_bytes()).await.unwrap();
-            wt.flush().await.unwrap();
+            // Seal the JSON frame and send its base64 ciphertext on the wire
+            let sealed = sealer.seal(message.to_wire().as_bytes()).expect("counter exhausted");
+            framed_wt.send(B64.encode(sealed)).await.unwrap();
         }
+        // Pin the Item type explicitly: LinesCodec's Encoder is generic over
+        // any AsRef<str>, and flush() alone gives the compiler nothing to
+        // infer it from.
+        SinkExt::<String>::flush(&mut framed_wt).await.unwrap();
     });
 
     // Receiving message task
-    let read_t = tokio::spawn(async move {
-        loop {
-            let mut buffer = vec![0; 1024];
-            let num_bytes = rd.read(&mut buffer).await.unwrap();
-            if num_bytes > 0 {
-                let as_str = String::from_utf8_lossy(&buffer[..num_bytes]).to_string();
-                receive_msg_tx.send(ChatMessage::from(as_str)).await.unwrap();
+    let mut read_t = tokio::spawn(async move {
+        // Each yielded frame is one complete line, i.e. one sealed message
+        while let Some(frame) = framed_rd.next().await {
+            match frame {
+                Ok(line) => {
+                    // A failed decode never touched the opener's counter, so it's
+                    // safe to skip. A failed open is different: the opener only
+                    // advances its counter on success while the server's sealer
+                    // always advances, so continuing past a failed open would
+                    // desync the two counters for good. End the connection instead.
+                    let Ok(ciphertext) = B64.decode(&line) else { continue };
+                    let Some(plaintext) = opener.open(&ciphertext) else { break };
+                    let line = String::from_utf8_lossy(&plaintext);
+                    match ServerEvent::from_wire(&line) {
+                        // A server shutdown ends the receive loop cleanly
+                        Ok(ServerEvent::Shutdown) => break,
+                        Ok(event) => receive_msg_tx.send(event).await.unwrap(),
+                        // Drop malformed frames silently: the TUI owns the
+                        // terminal in raw/alternate-screen mode, so writing to
+                        // stderr here would corrupt the rendered display.
+                        Err(_) => {}
+                    }
+                }
+                Err(_) => break,
             }
         }
     });
 
-    write_t.await.unwrap();
-    read_t.await.unwrap();
+    // Return as soon as either side finishes (UI quit or server shutdown).
+    // Selecting on `&mut` rather than the handles themselves keeps both alive
+    // in this scope so we can explicitly abort whichever one didn't finish —
+    // just dropping the losing JoinHandle detaches it instead of stopping it.
+    tokio::select! {
+        _ = &mut write_t => read_t.abort(),
+        _ = &mut read_t => write_t.abort(),
+    }
 }
 
-fn setup_app(send_msg_tx: Sender<ChatMessage>, receive_msg_rx: Receiver<ChatMessage>, username: String) -> Result<(Terminal<CrosstermBackend<std::io::Stdout>>, App), Box<dyn Error>> {
+type AppTerminal = Terminal<CrosstermBackend<std::io::Stdout>>;
+
+fn setup_app(send_msg_tx: Sender<ChatMessage>, receive_msg_rx: Receiver<ServerEvent>, username: String) -> Result<(AppTerminal, App), Box<dyn Error>> {
     enable_raw_mode()?;
 
     let mut stdout = std::io::stdout();
@@ -173,13 +235,13 @@ fn setup_app(send_msg_tx: Sender<ChatMessage>, receive_msg_rx: Receiver<ChatMess
     Ok(( terminal, App::new(send_msg_tx, receive_msg_rx, username) ))
 }
 
-fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, mut app: App) -> io::Result<()> {
+fn run_app(terminal: &mut AppTerminal, mut app: App) -> io::Result<()> {
     loop {
         terminal.draw(|f| ui(f, &app))?;
 
         if let Event::Key(key) = event::read()? {
-            match key.kind {
-                KeyEventKind::Press => match key.code {
+            if key.kind == KeyEventKind::Press {
+                match key.code {
                     KeyCode::Enter => app.submit_message(),
                     KeyCode::Char(to_insert) => {
                         app.enter_char(to_insert);
@@ -198,7 +260,6 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, mut app:
                     }
                     _ => {}
                 }
-                _ => {}
             }
         }
 
@@ -209,7 +270,7 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, mut app:
 
 }
 
-fn handle_app(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, app: App) -> Result<(), Box<dyn Error>> {
+fn handle_app(terminal: &mut AppTerminal, app: App) -> Result<(), Box<dyn Error>> {
     let res = run_app(terminal, app);
 
     // restore terminal
@@ -231,7 +292,7 @@ fn ui(f: &mut Frame, app: &App) {
         Constraint::Length(3),
         Constraint::Min(1),
     ]);
-    let [help_area, input_area, messages_area] = vertical.areas(f.size());
+    let [help_area, input_area, messages_area] = vertical.areas(f.area());
 
     let (msg, style) = (
         vec![
@@ -240,6 +301,7 @@ fn ui(f: &mut Frame, app: &App) {
             " to exit, ".into(),
             "Enter".bold(),
             " to send message".into(),
+            format!(" | {} online", app.users_online).into(),
         ],
         Style::default(),
     );
@@ -256,21 +318,25 @@ fn ui(f: &mut Frame, app: &App) {
     // Make the cursor visible and ask ratatui to put it at the specified coordinates after
     // rendering
     #[allow(clippy::cast_possible_truncation)]
-    f.set_cursor(
+    f.set_cursor_position((
         // Draw the cursor at the current position in the input field.
         // This position is can be controlled via the left and right arrow key
         input_area.x + app.character_index as u16 + 1,
         // Move one line down, from the border to the input line
         input_area.y + 1,
-    );
+    ));
 
     let messages: Vec<ListItem> = app
         .messages
         .iter()
-        .enumerate()
-        .map(|(_, m)| {
-            let content = Line::from(Span::raw(format!("{m}")));
-            ListItem::new(content)
+        .map(|m| {
+            // System lines (join/leave) are prefixed with "* " and dimmed
+            let span = if m.starts_with("* ") {
+                Span::styled(m.clone(), Style::default().add_modifier(Modifier::DIM))
+            } else {
+                Span::raw(m.clone())
+            };
+            ListItem::new(Line::from(span))
         })
         .collect();
     let messages =
@@ -287,15 +353,16 @@ fn main() {
     let (receive_msg_tx, receive_msg_rx) = mpsc::channel(2);
 
     let username = generate_name();
+    let net_username = username.clone();
 
     // Run network tasks
-    let _network_task = rt.spawn(async move {
-        run_network(receive_msg_tx, send_msg_rx).await; 
+    let network_task = rt.spawn(async move {
+        run_network(receive_msg_tx, send_msg_rx, net_username).await;
     });
 
-    // Prevents program from ending prematurly
-    // Setup and start UI
-    let _app_task = rt.block_on(async move {
+    // Setup and start UI. When the UI returns (Esc) its `send_msg_tx` is
+    // dropped, closing the channel so the network write task drains and flushes.
+    rt.block_on(async move {
         match setup_app(send_msg_tx, receive_msg_rx, username) {
             Ok((mut terminal, app)) => {
                 if let Err(e) = handle_app(&mut terminal, app) {
@@ -304,5 +371,9 @@ fn main() {
             }
             Err(e) => eprintln!("{}", e)
         }
+
+        // Let the network task flush the last queued message before we exit,
+        // but don't hang forever if the server is already gone
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(1), network_task).await;
     });
 }
\ No newline at end of file
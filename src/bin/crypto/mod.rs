@@ -0,0 +1,198 @@
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use chacha20poly1305::{aead::{Aead, KeyInit}, ChaCha20Poly1305, Key, Nonce};
+use futures::{SinkExt, StreamExt};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const NONCE_LEN: usize = 12;
+
+/// Failures in the encrypted transport. A handshake error or an exhausted
+/// counter aborts the connection; a failed open just discards the frame.
+#[derive(Debug)]
+pub enum CryptoError {
+    Handshake,
+    /// The per-message counter would wrap, which would reuse a nonce.
+    CounterExhausted,
+    Seal,
+}
+
+/// Per-message nonce: the base nonce XORed with the little-endian counter, plus
+/// a direction byte so the two halves of a connection never share a nonce.
+fn nonce_for(base: &[u8; NONCE_LEN], dir: u8, counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = *base;
+    let counter_bytes = counter.to_le_bytes();
+    for (n, c) in nonce.iter_mut().zip(counter_bytes.iter()) {
+        *n ^= c;
+    }
+    nonce[NONCE_LEN - 1] ^= dir;
+    nonce
+}
+
+/// Sealing half of an encrypted session: encrypts outgoing frames.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    base_nonce: [u8; NONCE_LEN],
+    dir: u8,
+    counter: u64,
+}
+
+impl Sealer {
+    /// Seal a plaintext frame, advancing the counter. Aborts rather than ever
+    /// reusing a nonce if the counter would wrap.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if self.counter == u64::MAX {
+            return Err(CryptoError::CounterExhausted);
+        }
+        let nonce = nonce_for(&self.base_nonce, self.dir, self.counter);
+        let ciphertext = self
+            .cipher
+            .encrypt(&Nonce::from(nonce), plaintext)
+            .map_err(|_| CryptoError::Seal)?;
+        self.counter += 1;
+        Ok(ciphertext)
+    }
+}
+
+/// Opening half of an encrypted session: decrypts incoming frames.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    base_nonce: [u8; NONCE_LEN],
+    dir: u8,
+    counter: u64,
+}
+
+impl Opener {
+    /// Open a sealed frame, advancing the counter on success. Returns `None`
+    /// for a frame that fails authentication, so the caller can discard it.
+    pub fn open(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if self.counter == u64::MAX {
+            return None;
+        }
+        let nonce = nonce_for(&self.base_nonce, self.dir, self.counter);
+        match self.cipher.decrypt(&Nonce::from(nonce), ciphertext) {
+            Ok(plaintext) => {
+                self.counter += 1;
+                Some(plaintext)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// Perform an ephemeral X25519 handshake over the framed stream: exchange
+/// public keys as the first frame, derive a shared secret and run it through
+/// HKDF to produce the ChaCha20-Poly1305 key and base nonce. The `initiator`
+/// flag keeps the two directions on distinct nonces.
+pub async fn handshake<R, W>(
+    rd: &mut FramedRead<R, LinesCodec>,
+    wt: &mut FramedWrite<W, LinesCodec>,
+    initiator: bool,
+) -> Result<(Sealer, Opener), CryptoError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+
+    // Send our public key, then read the peer's
+    wt.send(B64.encode(public.as_bytes()))
+        .await
+        .map_err(|_| CryptoError::Handshake)?;
+    let their_line = match rd.next().await {
+        Some(Ok(line)) => line,
+        _ => return Err(CryptoError::Handshake),
+    };
+    let their_bytes = B64.decode(their_line).map_err(|_| CryptoError::Handshake)?;
+    let their_arr: [u8; 32] = their_bytes.try_into().map_err(|_| CryptoError::Handshake)?;
+    let their_pub = PublicKey::from(their_arr);
+
+    let shared = secret.diffie_hellman(&their_pub);
+    let hk = Hkdf::<Sha256>::new(None, shared.as_bytes());
+    let mut okm = [0u8; 32 + NONCE_LEN];
+    hk.expand(b"rust-tcp-chat v1", &mut okm)
+        .map_err(|_| CryptoError::Handshake)?;
+
+    let key: [u8; 32] = okm[..32].try_into().expect("HKDF output is fixed-length");
+    let cipher = ChaCha20Poly1305::new(&Key::from(key));
+    let mut base_nonce = [0u8; NONCE_LEN];
+    base_nonce.copy_from_slice(&okm[32..]);
+
+    // Each side seals under its own direction byte and opens under the peer's
+    let (send_dir, recv_dir) = if initiator { (1, 2) } else { (2, 1) };
+    Ok((
+        Sealer { cipher: cipher.clone(), base_nonce, dir: send_dir, counter: 0 },
+        Opener { cipher, base_nonce, dir: recv_dir, counter: 0 },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build both halves of a session directly, as `handshake` would after
+    /// deriving a shared secret, without needing an actual socket pair.
+    fn session_pair() -> (Sealer, Opener, Sealer, Opener) {
+        let cipher = ChaCha20Poly1305::new(&Key::from([7u8; 32]));
+        let base_nonce = [9u8; NONCE_LEN];
+
+        let initiator_seal = Sealer { cipher: cipher.clone(), base_nonce, dir: 1, counter: 0 };
+        let initiator_open = Opener { cipher: cipher.clone(), base_nonce, dir: 2, counter: 0 };
+        let responder_seal = Sealer { cipher: cipher.clone(), base_nonce, dir: 2, counter: 0 };
+        let responder_open = Opener { cipher, base_nonce, dir: 1, counter: 0 };
+
+        (initiator_seal, initiator_open, responder_seal, responder_open)
+    }
+
+    #[test]
+    fn seal_then_open_round_trips() {
+        let (mut initiator_seal, _, _, mut responder_open) = session_pair();
+
+        let ciphertext = initiator_seal.seal(b"hello, chat").unwrap();
+        let plaintext = responder_open.open(&ciphertext).unwrap();
+
+        assert_eq!(plaintext, b"hello, chat");
+    }
+
+    #[test]
+    fn open_rejects_a_frame_sealed_under_the_wrong_direction() {
+        let (mut initiator_seal, mut initiator_open, ..) = session_pair();
+
+        // initiator_open expects frames under the responder's direction byte,
+        // not its own, so it must not be able to open its own sealed frame.
+        let ciphertext = initiator_seal.seal(b"hello, chat").unwrap();
+        assert!(initiator_open.open(&ciphertext).is_none());
+    }
+
+    #[test]
+    fn the_two_directions_never_share_a_nonce() {
+        let (mut initiator_seal, _, mut responder_seal, _) = session_pair();
+
+        // Same base nonce and counter, but opposite direction bytes, must still
+        // produce distinct ciphertexts for identical plaintext.
+        let from_initiator = initiator_seal.seal(b"hello").unwrap();
+        let from_responder = responder_seal.seal(b"hello").unwrap();
+
+        assert_ne!(from_initiator, from_responder);
+    }
+
+    #[test]
+    fn seal_refuses_to_reuse_a_nonce_at_counter_exhaustion() {
+        let (mut sealer, ..) = session_pair();
+        sealer.counter = u64::MAX;
+
+        assert!(matches!(sealer.seal(b"one more"), Err(CryptoError::CounterExhausted)));
+    }
+
+    #[test]
+    fn open_refuses_to_reuse_a_nonce_at_counter_exhaustion() {
+        let (_, mut opener, ..) = session_pair();
+        opener.counter = u64::MAX;
+
+        assert!(opener.open(&[0u8; 32]).is_none());
+    }
+}
@@ -1,96 +1,307 @@
 mod chat_message;
+mod crypto;
 
-use std::{net::SocketAddr, sync::Arc};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use tokio::{io::{AsyncReadExt, AsyncWriteExt, WriteHalf}, net::{TcpListener, TcpStream}};
-use tokio::sync::{mpsc, Mutex};
-use tokio::sync::mpsc::Receiver;
-use chat_message::ChatMessage;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::WriteHalf;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use futures::{SinkExt, StreamExt};
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use serde::Deserialize;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
+use chat_message::{ChatMessage, ServerEvent};
 
-type SocketWtsVec = Arc<Mutex<Vec<(SocketAddr, WriteHalf<TcpStream>)>>>;
+/// A server event paired with the address it originated from (so each
+/// client's write task can skip echoing a client's own messages back to it)
+/// and, for a `Chat`, the row id it was persisted under (so a freshly
+/// connected client's write task can skip a live delivery already covered by
+/// its history replay).
+type OriginEvent = (SocketAddr, Option<i64>, ServerEvent);
+
+/// Server configuration, read from `config.toml` at startup.
+#[derive(Deserialize)]
+struct Config {
+    bind_address: String,
+    db_path: String,
+    history_size: i64,
+}
 
 #[tokio::main]
 async fn main() {
-    let server_address = "127.0.0.1:6969";
-    let listener = TcpListener::bind(server_address).await.unwrap();
-    
-    // This will hold the write half of every opened sockets
-    let wt_sockets: SocketWtsVec = Arc::new(Mutex::new(Vec::new()));
+    // Load configuration, falling back to nothing: a missing/invalid file is a
+    // hard error since the bind address and DB live here.
+    let config: Config = toml::from_str(
+        &std::fs::read_to_string("config.toml").expect("failed to read config.toml"),
+    )
+    .expect("failed to parse config.toml");
 
-    println!("Listening on {}", server_address);
+    let listener = TcpListener::bind(&config.bind_address).await.unwrap();
 
-    // Create a channel where every message received from clients will be transmitted to the broadcast message task
-    let (tx, rx) = mpsc::channel(32);
+    // Open the SQLite history store and make sure the schema exists
+    let pool = SqlitePoolOptions::new().connect(&config.db_path).await.unwrap();
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            content TEXT NOT NULL,
+            ts INTEGER NOT NULL
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
 
-    // We must create the clone outside the spawned task
-    let wt_sockets_broadcast = wt_sockets.clone();
-    tokio::spawn(async move {
-        broadcast_messages(rx, wt_sockets_broadcast).await;
-    });
+    println!("Listening on {}", config.bind_address);
 
-    // Loop and accept an undefined amount of connections, normally this should be hard bounded to the max number in the channel (32)
+    // Single broadcast channel that every connection task subscribes to. This
+    // replaces the old shared Vec<WriteHalf> behind a mutex: each subscriber
+    // gets its own backpressure-aware queue instead of serializing behind one lock.
+    let (tx, _rx) = broadcast::channel::<OriginEvent>(32);
+
+    // Number of currently connected clients, surfaced to clients on join/leave
+    let users_online = Arc::new(AtomicUsize::new(0));
+
+    // Loop and accept an undefined amount of connections until Ctrl-C, which
+    // broadcasts a Shutdown so each write task can flush what's in flight
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        
-        println!("New user connected: {:?}", addr);
-
-        // Split the socket, keep the write portion somewhere else and use the read portion to receive messages
-        let (mut socket_rd, socket_wt) = tokio::io::split(socket);
-        wt_sockets.lock().await.push((addr.clone(), socket_wt));
-
-        // Clone that arc and pass it to the new task
-        let wt_sockets = wt_sockets.clone();
-        // Create a new channel sender, shadow the original one with a clone
-        let tx = tx.clone();
-        tokio::spawn(async move {
-            // This user can send an undefined amount of messages
-            loop {
-                // Receive message in the buffer
-                let mut buff = vec![0; 1024];
-                match socket_rd.read(&mut buff).await {
-                    Ok(num_bytes) => {
-                        if num_bytes > 0 {
-                            // Format and send the data through the channel
-                            let message_packet = String::from_utf8_lossy(&buff[..num_bytes]).to_string();
-                            let chat_message = ChatMessage::from(message_packet);
-                            tx.send((addr, chat_message)).await.unwrap();
-                        }
-                    },
-                    Err(_) => {
-                        println!("Connection closed with {:?}", addr);
-                        // Remove that socket from the list
-                        let mut wt_sockets = wt_sockets.lock().await;
-                        let idx = wt_sockets.iter().position(|e| e.0 == addr);
-                        if let Some(idx) = idx {
-                            println!("Removing idx #{}", idx);
-                            wt_sockets.remove(idx);
-                        }
-                        break
-                    }
-                }
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, addr) = accepted.unwrap();
+
+                println!("New user connected: {:?}", addr);
+
+                // Split the socket and frame each half
+                let (socket_rd, socket_wt) = tokio::io::split(socket);
+                let framed_rd = FramedRead::new(socket_rd, LinesCodec::new());
+                let framed_wt = FramedWrite::new(socket_wt, LinesCodec::new());
+
+                // Each connection gets a sender clone to publish on; it subscribes
+                // its own receiver once its history replay is done, so a message
+                // broadcast in between can't land in both the replay and the feed.
+                let tx = tx.clone();
+                let users_online = users_online.clone();
+                let pool = pool.clone();
+                let history_size = config.history_size;
+
+                tokio::spawn(async move {
+                    connection(addr, framed_rd, framed_wt, tx, users_online, pool, history_size).await;
+                });
             }
-            println!("Killing task for {:?}", addr);
-        });
+            _ = tokio::signal::ctrl_c() => {
+                println!("Shutting down, notifying clients...");
+                let local = listener.local_addr().unwrap();
+                let _ = tx.send((local, None, ServerEvent::Shutdown));
+                // Give the connection tasks a moment to drain and flush their sockets
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                break;
+            }
+        }
     }
 }
 
-// Broadcast every received message
-async fn broadcast_messages(mut rx: Receiver<(SocketAddr, ChatMessage)>, wt_sockets: SocketWtsVec) {
-    // Start listening to received messages
-    while let Some((sender_addr, message)) = rx.recv().await {
+/// Drive a single client: a read side that publishes incoming chat onto the
+/// broadcast, and a write side that forwards every event (except the client's
+/// own chat echoes) down its socket.
+async fn connection(
+    addr: SocketAddr,
+    mut framed_rd: FramedRead<tokio::io::ReadHalf<TcpStream>, LinesCodec>,
+    mut framed_wt: FramedWrite<WriteHalf<TcpStream>, LinesCodec>,
+    tx: broadcast::Sender<OriginEvent>,
+    users_online: Arc<AtomicUsize>,
+    pool: SqlitePool,
+    history_size: i64,
+) {
+    // Establish the encrypted session before any message flows. The server is
+    // the responder in the Diffie-Hellman handshake.
+    let (mut sealer, mut opener) = match crypto::handshake(&mut framed_rd, &mut framed_wt, false).await {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Handshake with {:?} failed: {:?}", addr, e);
+            return;
+        }
+    };
+
+    // The first sealed frame a client sends is its generated username, so we can
+    // label its presence without waiting for it to say something
+    let username = match framed_rd.next().await {
+        Some(Ok(line)) => match B64.decode(&line).ok().and_then(|ct| opener.open(&ct)) {
+            Some(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            None => {
+                println!("Bad handshake frame from {:?}", addr);
+                return;
+            }
+        },
+        _ => {
+            println!("Connection closed with {:?} before handshake", addr);
+            return;
+        }
+    };
 
-        // Display some server side info
-        println!("Received message from {} => {}", message.username, message.content);
+    // Subscribe to the live feed before reading any history, so a message
+    // broadcast while the history query is in flight is caught here instead of
+    // being dropped on the floor. We then bound the replay to the snapshot
+    // of rows that already existed at that point, so nothing arrives twice.
+    let mut rx = tx.subscribe();
+    let snapshot_id: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM messages")
+        .fetch_one(&pool)
+        .await
+        .unwrap_or(0);
 
-        // Hold the write part of every opened sockets
-        let mut wt_sockets = wt_sockets.lock().await;
+    // Replay the most recent history up to the snapshot to just this socket
+    // before it joins the live feed, so a reconnecting client sees recent
+    // context (newest-last).
+    let history = sqlx::query_as::<_, (String, String)>(
+        "SELECT username, content FROM (
+            SELECT id, username, content FROM messages WHERE id <= ? ORDER BY id DESC LIMIT ?
+         ) ORDER BY id ASC",
+    )
+    .bind(snapshot_id)
+    .bind(history_size)
+    .fetch_all(&pool)
+    .await
+    .unwrap_or_default();
+    for (username, content) in history {
+        let event = ServerEvent::Chat(ChatMessage { username, content });
+        let sealed = sealer.seal(event.to_wire().as_bytes()).expect("counter exhausted");
+        // A client disconnecting mid-replay is normal; bail out instead of
+        // panicking the task, same as a failed read elsewhere in this function.
+        if framed_wt.send(B64.encode(sealed)).await.is_err() {
+            println!("Connection closed with {:?} during history replay", addr);
+            return;
+        }
+    }
+
+    // Count this client in and announce its arrival, including the live total
+    let count = users_online.fetch_add(1, Ordering::SeqCst) + 1;
+    println!("{} joined ({} online)", username, count);
+    let _ = tx.send((addr, None, ServerEvent::Joined { username: username.clone(), users_online: count }));
+
+    // The broadcast above is skipped for this same socket by the self-echo
+    // guard below, so it would never otherwise learn the current count; tell
+    // it directly before the write task starts.
+    let presence = ServerEvent::Presence { users_online: count };
+    let sealed = sealer.seal(presence.to_wire().as_bytes()).expect("counter exhausted");
+    if framed_wt.send(B64.encode(sealed)).await.is_err() {
+        println!("Connection closed with {:?} right after joining", addr);
+        return;
+    }
 
-        // Send back the message, except to the original sender
-        for (addr, wt) in wt_sockets.iter_mut() {
-            if *addr != sender_addr {
-                wt.write(message.to_string().as_bytes()).await.unwrap();
-                wt.flush().await.unwrap();
+    // Write task: fan out broadcast events to this client
+    let write_t = tokio::spawn(async move {
+        // Helper that seals an event and writes its base64 ciphertext
+        macro_rules! send_sealed {
+            ($wt:expr, $sealer:expr, $event:expr) => {{
+                let sealed = $sealer.seal($event.to_wire().as_bytes()).expect("counter exhausted");
+                $wt.send(B64.encode(sealed)).await.unwrap();
+            }};
+        }
+
+        // A Chat event whose row id falls at or before this connection's history
+        // snapshot was already delivered by the replay above; skip it here so it
+        // doesn't land twice regardless of exactly when we subscribed relative
+        // to the snapshot query.
+        let already_replayed = |row_id: Option<i64>, event: &ServerEvent| {
+            matches!((row_id, event), (Some(id), ServerEvent::Chat(_)) if id <= snapshot_id)
+        };
+
+        loop {
+            let (origin, row_id, event) = match rx.recv().await {
+                Ok(v) => v,
+                // A slow client got more than the channel's capacity behind; skip
+                // the events it missed rather than tearing down the write side,
+                // since it's still connected and able to receive new ones.
+                Err(RecvError::Lagged(n)) => {
+                    eprintln!("Write task for {:?} lagged, dropped {} events", addr, n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            if already_replayed(row_id, &event) {
+                continue;
             }
+
+            // Don't echo a client's own chat message or presence notice back to it
+            if origin == addr && matches!(event, ServerEvent::Chat(_) | ServerEvent::Joined { .. } | ServerEvent::Left { .. }) {
+                continue;
+            }
+
+            if let ServerEvent::Shutdown = event {
+                // Flush anything still queued before forwarding the shutdown, so
+                // no in-flight message is lost when the socket goes away
+                while let Ok((o, row_id, queued)) = rx.try_recv() {
+                    if already_replayed(row_id, &queued) {
+                        continue;
+                    }
+                    if o == addr && matches!(queued, ServerEvent::Chat(_) | ServerEvent::Joined { .. } | ServerEvent::Left { .. }) {
+                        continue;
+                    }
+                    send_sealed!(framed_wt, sealer, queued);
+                }
+                send_sealed!(framed_wt, sealer, event);
+                // Pin the Item type explicitly: LinesCodec's Encoder is generic
+                // over any AsRef<str>, and flush() alone gives the compiler
+                // nothing to infer it from.
+                SinkExt::<String>::flush(&mut framed_wt).await.unwrap();
+                break;
+            }
+
+            send_sealed!(framed_wt, sealer, event);
+        }
+    });
+
+    // Read task: one complete frame per message. A decode error or a closed
+    // socket both terminate this client.
+    while let Some(Ok(line)) = framed_rd.next().await {
+        // `Opener::open` only advances its counter on a successful open,
+        // while the peer's `Sealer` always advances on seal. Skipping a
+        // frame that fails authentication and continuing would leave the
+        // two counters permanently out of step, dooming every frame
+        // after it, so treat it as connection-ending instead.
+        let Some(plaintext) = B64.decode(&line).ok().and_then(|ct| opener.open(&ct)) else {
+            eprintln!("Ending connection with {:?}: frame failed authentication", addr);
+            break;
+        };
+        let line = String::from_utf8_lossy(&plaintext);
+        // Parse the frame and publish it, skipping malformed frames
+        match ChatMessage::from_wire(&line) {
+            Ok(chat_message) => {
+                println!("Received message from {} => {}", chat_message.username, chat_message.content);
+                // Persist before broadcasting so it's part of future replays
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0);
+                let row_id = match sqlx::query("INSERT INTO messages (username, content, ts) VALUES (?, ?, ?)")
+                    .bind(&chat_message.username)
+                    .bind(&chat_message.content)
+                    .bind(ts)
+                    .execute(&pool)
+                    .await
+                {
+                    Ok(result) => Some(result.last_insert_rowid()),
+                    Err(e) => {
+                        eprintln!("Failed to persist message: {e}");
+                        None
+                    }
+                };
+                let _ = tx.send((addr, row_id, ServerEvent::Chat(chat_message)));
+            }
+            Err(e) => eprintln!("Skipping malformed frame from {:?}: {e}", addr),
         }
     }
-}
\ No newline at end of file
+
+    let count = users_online.fetch_sub(1, Ordering::SeqCst) - 1;
+    println!("Connection closed with {:?} ({} online)", addr, count);
+    let _ = tx.send((addr, None, ServerEvent::Left { username: username.clone(), users_online: count }));
+
+    println!("Killing task for {:?}", addr);
+    write_t.abort();
+}